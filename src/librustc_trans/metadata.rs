@@ -8,20 +8,59 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+// `ar`, `memmap`, and `object` back `ObjectMetadataLoader` below. This
+// checkout doesn't carry this crate's `Cargo.toml`, so the corresponding
+// `[dependencies]` entries can't be added here; whoever lands this against
+// the full tree needs to add them there (and, since `rustc_trans` doesn't
+// otherwise gate code on `cfg(test)` here, run `cargo test` once that's
+// done to exercise the `tests` module below).
+extern crate ar;
+extern crate memmap;
+extern crate object;
+
 use rustc::util::common;
 use rustc::middle::cstore::MetadataLoader;
-use rustc_back::target::Target;
+use rustc_back::target::{Target, TargetOptions};
 use llvm;
 use llvm::{False, ObjectFile, mk_section_iter};
 use llvm::archive_ro::ArchiveRO;
 
 use rustc_data_structures::owning_ref::{ErasedBoxRef, OwningRef};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 use std::ptr;
 use std::slice;
 
+use self::object::Object;
+
 pub const METADATA_FILENAME: &str = "rust.metadata.bin";
 
+/// Which `MetadataLoader` implementation to construct.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MetadataLoaderKind {
+    /// Read metadata through LLVM's `ArchiveRO`/`ObjectFile` bindings.
+    /// This is what `rustc_trans` itself always uses, since it links
+    /// against LLVM regardless.
+    Llvm,
+    /// Read metadata with the native `ar`/`object` parsers, for embedders
+    /// of this module that don't want to link LLVM.
+    Object,
+}
+
+/// Constructs the requested `MetadataLoader`.
+///
+/// Wiring `MetadataLoaderKind` up to a `-Z`/`-C` flag belongs in
+/// `rustc::session::config`, which isn't part of this crate; callers
+/// embedding `rustc_trans` directly can pass `MetadataLoaderKind::Object`
+/// themselves in the meantime.
+pub fn create_metadata_loader(kind: MetadataLoaderKind) -> Box<MetadataLoader + Sync + Send> {
+    match kind {
+        MetadataLoaderKind::Llvm => box LlvmMetadataLoader,
+        MetadataLoaderKind::Object => box ObjectMetadataLoader,
+    }
+}
+
 pub struct LlvmMetadataLoader;
 
 impl MetadataLoader for LlvmMetadataLoader {
@@ -70,11 +109,77 @@ impl MetadataLoader for LlvmMetadataLoader {
     }
 }
 
+/// A `MetadataLoader` that reads rlib and dylib metadata with a native
+/// Rust `ar`/`object` parser instead of going through LLVM's `ArchiveRO`
+/// and `ObjectFile` FFI bindings.
+pub struct ObjectMetadataLoader;
+
+impl MetadataLoader for ObjectMetadataLoader {
+    fn get_rlib_metadata(&self, _: &Target, filename: &Path) -> Result<ErasedBoxRef<[u8]>, String> {
+        find_rlib_metadata(filename)
+    }
+
+    fn get_dylib_metadata(&self,
+                          target: &Target,
+                          filename: &Path)
+                          -> Result<ErasedBoxRef<[u8]>, String> {
+        let file = File::open(filename).map_err(|e| {
+            format!("error reading library: '{}': {}", filename.display(), e)
+        })?;
+        let mmap = unsafe {
+            memmap::Mmap::map(&file).map_err(|e| {
+                format!("error reading library: '{}': {}", filename.display(), e)
+            })?
+        };
+        let of = object::File::parse(&mmap[..]).map_err(|e| {
+            format!("provided path not an object file: '{}': {}", filename.display(), e)
+        })?;
+        // The object file (and the mmap it borrows from) only need to live
+        // long enough to copy the section out; the returned metadata owns
+        // its buffer rather than borrowing from either.
+        //
+        // `object::MachOSection::name` surfaces the bare sectname, same as
+        // LLVM's section iterator (see `read_metadata_section_name`), so
+        // this uses the same candidate list as the LLVM-backed loader.
+        let data = read_metadata_section_name(&target.options).iter()
+            .filter_map(|name| of.section_data_by_name(name))
+            .next()
+            .ok_or_else(|| format!("metadata not found: '{}'", filename.display()))?
+            .into_owned();
+        Ok(OwningRef::new(box data).map(|data| &data[..]).erase_owner())
+    }
+}
+
+/// Scans an rlib's `ar` archive for the `rust.metadata.bin` member.
+///
+/// This doesn't need a `Target`: unlike the dylib path, rlib metadata
+/// members aren't named per-platform.
+fn find_rlib_metadata(filename: &Path) -> Result<ErasedBoxRef<[u8]>, String> {
+    let file = File::open(filename).map_err(|e| {
+        format!("failed to open rlib '{}': {}", filename.display(), e)
+    })?;
+    let mut archive = ar::Archive::new(file);
+    while let Some(entry) = archive.next_entry() {
+        let mut entry = entry.map_err(|e| {
+            format!("failed to read rlib metadata in '{}': {}", filename.display(), e)
+        })?;
+        if entry.header().identifier() == METADATA_FILENAME.as_bytes() {
+            let mut data = Vec::with_capacity(entry.header().size() as usize);
+            entry.read_to_end(&mut data).map_err(|e| {
+                format!("failed to read rlib metadata in '{}': {}", filename.display(), e)
+            })?;
+            return Ok(OwningRef::new(box data).map(|data| &data[..]).erase_owner());
+        }
+    }
+    Err(format!("failed to read rlib metadata: '{}'", filename.display()))
+}
+
 fn search_meta_section<'a>(of: &'a ObjectFile,
                            target: &Target,
                            filename: &Path)
                            -> Result<&'a [u8], String> {
     unsafe {
+        let accepted_names = read_metadata_section_name(&target.options);
         let si = mk_section_iter(of.llof);
         while llvm::LLVMIsSectionIteratorAtEnd(of.llof, si.llsi) == False {
             let mut name_buf = ptr::null();
@@ -82,7 +187,7 @@ fn search_meta_section<'a>(of: &'a ObjectFile,
             let name = slice::from_raw_parts(name_buf as *const u8, name_len as usize).to_vec();
             let name = String::from_utf8(name).unwrap();
             debug!("get_metadata_section: name {}", name);
-            if read_metadata_section_name(target) == name {
+            if accepted_names.contains(&name.as_str()) {
                 let cbuf = llvm::LLVMGetSectionContents(si.llsi);
                 let csz = llvm::LLVMGetSectionSize(si.llsi) as usize;
                 // The buffer is valid while the object file is around
@@ -117,6 +222,81 @@ pub fn metadata_section_name(target: &Target) -> &'static str {
     }
 }
 
-fn read_metadata_section_name(_target: &Target) -> &'static str {
-    ".rustc"
+/// Returns the section names `search_meta_section` will accept for this
+/// target, most-preferred first.
+///
+/// `metadata_section_name` writes the segment-qualified `__DATA,.rustc`
+/// name into the object file on osx, but every section iterator we read
+/// it back with — LLVM's `SectionRef::getName` and the `object` crate's
+/// `MachOSection::name` alike — reports only the bare sectname `.rustc`.
+/// So unlike the write side, the read side has no osx-specific case: the
+/// segment-qualified spelling never actually shows up here.
+fn read_metadata_section_name(options: &TargetOptions) -> Vec<&'static str> {
+    if options.is_like_windows {
+        // Older compilers wrote the unabridged `.note.rustc` name, which
+        // link.exe truncates to 8 characters per the COFF/PE caveat
+        // documented in `metadata_section_name`. Accept the truncated
+        // spelling too so metadata written by those compilers can still be
+        // read back.
+        vec![".rustc", ".note.ru"]
+    } else {
+        vec![".rustc"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_metadata_section_name_matches_bare_sectname_on_osx() {
+        let options = TargetOptions { is_like_osx: true, ..Default::default() };
+        assert_eq!(read_metadata_section_name(&options), vec![".rustc"]);
+    }
+
+    #[test]
+    fn read_metadata_section_name_accepts_truncated_coff_name_on_windows() {
+        let options = TargetOptions { is_like_windows: true, ..Default::default() };
+        assert!(read_metadata_section_name(&options).contains(&".note.ru"));
+    }
+
+    #[test]
+    fn read_metadata_section_name_defaults_to_rustc_elsewhere() {
+        let options = TargetOptions::default();
+        assert_eq!(read_metadata_section_name(&options), vec![".rustc"]);
+    }
+
+    #[test]
+    fn find_rlib_metadata_reads_back_the_archive_member() {
+        let path = std::env::temp_dir()
+            .join(format!("rustc-metadata-loader-test-{}.rlib", std::process::id()));
+        let expected = b"fake crate metadata".to_vec();
+        {
+            let file = File::create(&path).unwrap();
+            let mut builder = ar::Builder::new(file);
+            let header = ar::Header::new(METADATA_FILENAME.as_bytes().to_vec(),
+                                          expected.len() as u64);
+            builder.append(&header, &expected[..]).unwrap();
+        }
+        let result = find_rlib_metadata(&path);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(&*result.unwrap(), &expected[..]);
+    }
+
+    #[test]
+    fn find_rlib_metadata_errors_when_member_missing() {
+        let path = std::env::temp_dir()
+            .join(format!("rustc-metadata-loader-test-empty-{}.rlib", std::process::id()));
+        {
+            let file = File::create(&path).unwrap();
+            let mut builder = ar::Builder::new(file);
+            // A valid archive that holds some other member, so the lookup
+            // itself has to come up empty.
+            let header = ar::Header::new(b"lib.o".to_vec(), 0);
+            builder.append(&header, &[][..]).unwrap();
+        }
+        let result = find_rlib_metadata(&path);
+        std::fs::remove_file(&path).ok();
+        assert!(result.is_err());
+    }
 }